@@ -5,8 +5,217 @@ use std::{
     os::unix::fs::FileExt,
     time::{SystemTime, UNIX_EPOCH},
 };
+
+use aes_gcm::Aes256Gcm;
+use aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng};
+use argon2::Argon2;
+use base64::{engine::general_purpose::STANDARD as base64_engine, Engine as _};
+use chacha20poly1305::ChaCha20Poly1305;
+use crc32fast::Hasher;
+use lz4_flex::block::{compress as lz4_compress, decompress as lz4_decompress};
+
+// Per-record compression codec. Stored as a 1-byte tag alongside the value so
+// a single file can mix compressed and uncompressed records (e.g. after
+// toggling compression on an existing engine) and still replay correctly.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Codec {
+    None,
+    Lz4,
+}
+
+impl Codec {
+    fn to_byte(self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Lz4 => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> io::Result<Self> {
+        match byte {
+            0 => Ok(Codec::None),
+            1 => Ok(Codec::Lz4),
+            other => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown codec tag {}", other),
+            )),
+        }
+    }
+}
+
+// File header: an 8-byte magic signature, a 1-byte format version, a 1-byte
+// encryption algorithm tag, and a 16-byte Argon2 salt (zeroed when the file
+// isn't encrypted). The signature is PNG-style (non-ASCII leading byte +
+// CR/LF + EOF byte) so a truncated or text-mangled file (e.g. CRLF-translated
+// by a bad transfer) is caught immediately instead of being misread as the
+// first record.
+const WAL_MAGIC: [u8; 8] = [0xEE, b'W', b'A', b'L', 0x0D, 0x0A, 0x1A, 0x00];
+const WAL_FORMAT_VERSION: u8 = 2;
+const SALT_LEN: usize = 16;
+const HEADER_LEN: u64 = WAL_MAGIC.len() as u64 + 1 + 1 + SALT_LEN as u64;
+
+// Selects the AEAD used to seal each record's key+value payload. Stored as a
+// 1-byte tag in the file header: 0 means the file is plaintext, in which case
+// there is no `EncryptionAlgo` value at all (see `EncryptionKey`, which is
+// simply absent for plaintext files).
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum EncryptionAlgo {
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl EncryptionAlgo {
+    fn to_byte(self) -> u8 {
+        match self {
+            EncryptionAlgo::Aes256Gcm => 1,
+            EncryptionAlgo::ChaCha20Poly1305 => 2,
+        }
+    }
+
+    // `None` means the file is plaintext (tag 0).
+    fn from_byte(byte: u8) -> io::Result<Option<Self>> {
+        match byte {
+            0 => Ok(None),
+            1 => Ok(Some(EncryptionAlgo::Aes256Gcm)),
+            2 => Ok(Some(EncryptionAlgo::ChaCha20Poly1305)),
+            other => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown encryption algorithm tag {}", other),
+            )),
+        }
+    }
+}
+
+// Derived once at open time from the passphrase + header salt, then reused
+// to seal/open every record. Absent entirely on a plaintext engine.
+struct EncryptionKey {
+    algo: EncryptionAlgo,
+    key: [u8; 32],
+}
+
+impl EncryptionKey {
+    fn derive(passphrase: &str, salt: &[u8; SALT_LEN], algo: EncryptionAlgo) -> Self {
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+            .expect("argon2 key derivation failed");
+        Self { algo, key }
+    }
+
+    // Encrypts `plaintext` with a fresh random nonce, returning `(nonce, ciphertext+tag)`.
+    fn seal(&self, plaintext: &[u8]) -> ([u8; 12], Vec<u8>) {
+        match self.algo {
+            EncryptionAlgo::Aes256Gcm => {
+                let cipher = Aes256Gcm::new_from_slice(&self.key).unwrap();
+                let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+                let ciphertext = cipher.encrypt(&nonce, plaintext).expect("encryption failed");
+                (nonce.into(), ciphertext)
+            }
+            EncryptionAlgo::ChaCha20Poly1305 => {
+                let cipher = ChaCha20Poly1305::new_from_slice(&self.key).unwrap();
+                let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+                let ciphertext = cipher.encrypt(&nonce, plaintext).expect("encryption failed");
+                (nonce.into(), ciphertext)
+            }
+        }
+    }
+
+    // Decrypts and authenticates `ciphertext`, surfacing a failed AEAD tag
+    // check as an `io::Error` instead of panicking.
+    fn open(&self, nonce: &[u8; 12], ciphertext: &[u8]) -> io::Result<Vec<u8>> {
+        let auth_failed =
+            || io::Error::new(io::ErrorKind::InvalidData, "AEAD authentication failed");
+        match self.algo {
+            EncryptionAlgo::Aes256Gcm => {
+                let cipher = Aes256Gcm::new_from_slice(&self.key).unwrap();
+                cipher
+                    .decrypt(aes_gcm::Nonce::from_slice(nonce), ciphertext)
+                    .map_err(|_| auth_failed())
+            }
+            EncryptionAlgo::ChaCha20Poly1305 => {
+                let cipher = ChaCha20Poly1305::new_from_slice(&self.key).unwrap();
+                cipher
+                    .decrypt(chacha20poly1305::Nonce::from_slice(nonce), ciphertext)
+                    .map_err(|_| auth_failed())
+            }
+        }
+    }
+}
 fn main() {
-    let mut se = StorageEngine::new(&"db.wal".to_string());
+    let args: Vec<String> = std::env::args().collect();
+    let backend_name = args
+        .iter()
+        .position(|a| a == "--backend")
+        .and_then(|i| args.get(i + 1))
+        .map(String::as_str)
+        .unwrap_or("file");
+
+    let passphrase = args
+        .iter()
+        .position(|a| a == "--passphrase")
+        .and_then(|i| args.get(i + 1));
+
+    let compress = args.iter().any(|a| a == "--compress");
+
+    // Overrides FileBackend's default auto-compaction trigger ratio (see
+    // `FileBackend::maybe_compact`); falls back to the default on a missing
+    // or unparsable value.
+    let compaction_ratio = args
+        .iter()
+        .position(|a| a == "--compaction-ratio")
+        .and_then(|i| args.get(i + 1))
+        .and_then(|v| v.parse::<i32>().ok());
+
+    // Values typed at the REPL are plain text by default; `--binary` treats
+    // SET's value argument as base64 so arbitrary bytes (images, serialized
+    // structs, etc.) can round-trip through a terminal, and GET prints values
+    // back out as base64 instead of assuming they're UTF-8.
+    let binary = args.iter().any(|a| a == "--binary");
+
+    // Opening a file backend can fail (e.g. a wrong passphrase on an
+    // encrypted db.wal); report it and exit instead of panicking or, worse,
+    // silently wiping the file.
+    let open_file_backend = |compress: bool| -> Box<dyn StorageBackend> {
+        match FileBackend::new_with_compression(&"db.wal".to_string(), compress) {
+            Ok(mut engine) => {
+                if let Some(ratio) = compaction_ratio {
+                    engine.set_compaction_ratio(ratio);
+                }
+                Box::new(engine)
+            }
+            Err(err) => {
+                eprintln!("Failed to open db.wal: {}", err);
+                std::process::exit(1);
+            }
+        }
+    };
+
+    let mut se: Box<dyn StorageBackend> = match (backend_name, passphrase) {
+        ("memory", _) => Box::new(MemoryBackend::new_with_compression(compress)),
+        ("file", Some(phrase)) => match FileBackend::new_with_passphrase_and_compression(
+            &"db.wal".to_string(),
+            phrase,
+            EncryptionAlgo::ChaCha20Poly1305,
+            compress,
+        ) {
+            Ok(mut engine) => {
+                if let Some(ratio) = compaction_ratio {
+                    engine.set_compaction_ratio(ratio);
+                }
+                Box::new(engine)
+            }
+            Err(err) => {
+                eprintln!("Failed to open db.wal: {}", err);
+                std::process::exit(1);
+            }
+        },
+        ("file", None) => open_file_backend(compress),
+        (other, _) => {
+            eprintln!("Unknown --backend '{}', falling back to 'file'", other);
+            open_file_backend(compress)
+        }
+    };
+
     loop {
         let mut cmd = String::new();
         io::stdin()
@@ -60,85 +269,338 @@ fn main() {
         // Execute the action
         match action_lower.as_str() {
             "set" => {
-                se.set(key.clone(), val.clone());
+                let val_bytes = if binary {
+                    match base64_engine.decode(&val) {
+                        Ok(bytes) => bytes,
+                        Err(err) => {
+                            println!("Invalid base64 value: {}", err);
+                            continue;
+                        }
+                    }
+                } else {
+                    val.clone().into_bytes()
+                };
+                se.set(key.clone().into_bytes(), val_bytes);
                 println!(">> {} = {}", key, val);
             }
-            "get" => match se.get(key.clone()) {
-                Some(value) => println!(">> {} = {}", key, value),
-                None => println!("Key '{}' not found", key),
+            "get" => match se.get(key.clone().into_bytes()) {
+                Ok(Some(value)) => {
+                    if binary {
+                        println!(">> {} = {}", key, base64_engine.encode(&value));
+                    } else {
+                        match String::from_utf8(value) {
+                            Ok(s) => println!(">> {} = {}", key, s),
+                            Err(_) => {
+                                println!("Value for '{}' is not valid UTF-8; retry with --binary", key)
+                            }
+                        }
+                    }
+                }
+                Ok(None) => println!("Key '{}' not found", key),
+                Err(err) => println!("Error reading key '{}': {}", key, err),
             },
             "del" => {
-                se.delete(key.clone());
+                se.delete(key.clone().into_bytes());
                 println!("DEL {}", key);
             }
             "showkeys" => {
-                todo!("SHOWKEYS not yet supported")
+                // `key`, if given, is treated as a prefix filter; an empty
+                // prefix (no argument) matches every live key.
+                let prefix = key.clone().into_bytes();
+                let matching: Vec<Vec<u8>> = se
+                    .iter_keys()
+                    .into_iter()
+                    .filter(|k| k.starts_with(&prefix))
+                    .collect();
+
+                if matching.is_empty() {
+                    println!("No keys found");
+                } else {
+                    for k in matching {
+                        match String::from_utf8(k.clone()) {
+                            Ok(s) => println!("{}", s),
+                            Err(_) => println!("{}", base64_engine.encode(&k)),
+                        }
+                    }
+                }
             }
             _ => unreachable!(),
         }
     }
 }
 
-// Log-based storage engine
+// The set/get/delete/compact/key-iteration surface every storage engine
+// supports, independent of where the bytes actually live. This lets the REPL
+// pick a backend at startup instead of hardwiring a Unix file.
+trait StorageBackend {
+    fn set(&mut self, key: Vec<u8>, val: Vec<u8>);
+    // Returns an error (rather than panicking) when a record is unreadable,
+    // e.g. a failed AEAD authentication check on an encrypted backend.
+    fn get(&mut self, key: Vec<u8>) -> io::Result<Option<Vec<u8>>>;
+    fn delete(&mut self, key: Vec<u8>);
+    fn compact(&mut self);
+    fn keys(&self) -> Vec<Vec<u8>>;
+
+    // Live keys (tombstones excluded, since a deleted key never stays in
+    // `key_position_map`) in sorted order. `FileBackend` and `MemoryBackend`
+    // share identical semantics here, so this is a default method rather
+    // than being reimplemented per backend.
+    fn iter_keys(&self) -> Vec<Vec<u8>> {
+        let mut keys = self.keys();
+        keys.sort();
+        keys
+    }
+
+    // Live key/value pairs with keys in `[start, end)`, in sorted order.
+    // Skips tombstones and, on an encrypted backend, any record that fails
+    // to authenticate, the same way `compact` does. Also a default method
+    // for the same reason as `iter_keys`.
+    fn scan(&mut self, start: &[u8], end: &[u8]) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut pairs = Vec::new();
+        for key in self.iter_keys() {
+            if key.as_slice() < start || key.as_slice() >= end {
+                continue;
+            }
+            if let Some(val) = self.get(key.clone())? {
+                pairs.push((key, val));
+            }
+        }
+        Ok(pairs)
+    }
+}
+
+// Log-based storage engine backed by a real file on disk.
 // Uses Write-ahead Log with periodic cleanup.
-struct StorageEngine {
+struct FileBackend {
     db_file: File,
+    path: String,
     // map key start to file position
-    key_position_map: HashMap<String, u64>,
+    key_position_map: HashMap<Vec<u8>, u64>,
     sequence_number: i32,
-    // WAL Line format:
-    // [16B Unix Millis Timestamp] [4B key len] [ 4B val len ] [ key bytes ] [ val_bytes ]
+    // None for a plaintext file; Some once opened with a passphrase.
+    encryption: Option<EncryptionKey>,
+    // The Argon2 salt from the file header; zeroed for a plaintext file.
+    // Kept around so `compact()` can rewrite the header without re-deriving it.
+    salt: [u8; SALT_LEN],
+    // Whether newly-written records should be LZ4-compressed. This only
+    // affects what `set`/`compact` write going forward; the codec byte is
+    // stored per record, so a single file can freely mix compressed and
+    // uncompressed records.
+    compress: bool,
+    // See `maybe_compact`; defaults to `DEFAULT_COMPACTION_RATIO` and can be
+    // overridden with `set_compaction_ratio`.
+    compaction_ratio: i32,
+    // WAL Line format, plaintext:
+    // [16B Unix Millis Timestamp] [4B key len] [4B original val len] [1B codec] [4B stored val len] [ key bytes ] [ stored val bytes ] [ 4B CRC32 ]
+    // WAL Line format, encrypted:
+    // [16B Unix Millis Timestamp] [12B nonce] [4B ciphertext len] [ ciphertext+tag ]
+    // where the ciphertext seals a plaintext payload of
+    // [4B key len][4B original val len][1B codec][4B stored val len][key][stored val].
     // If val len is 0 bytes, we assume it's deleted.
+    // The CRC32 (plaintext mode) or AEAD tag (encrypted mode) lets replay detect
+    // a torn write or bit flip instead of trusting the disk blindly.
+    // The codec byte (0 = none, 1 = LZ4) is per record, not per file, so
+    // toggling `compress` never breaks replay of records written before the
+    // change.
+}
+
+// Writes `contents` to `tmp_path`, fsyncs it, renames it over `dest_path`,
+// then fsyncs the containing directory. The fsyncs are what make the
+// rename's atomicity actually crash-safe: without them, a power loss can
+// still land a truncated or zeroed file at `dest_path`, or leave the
+// directory entry pointing at the old inode, because the write or the
+// rename's metadata update was never durably flushed. Used by both
+// `FileBackend::upgrade` and `FileBackend::compact`.
+fn write_and_rename_durably(tmp_path: &str, dest_path: &str, contents: &[u8]) -> io::Result<()> {
+    let tmp_file = File::create(tmp_path)?;
+    (&tmp_file).write_all_at(contents, 0)?;
+    tmp_file.sync_all()?;
+    drop(tmp_file);
+
+    std::fs::rename(tmp_path, dest_path)?;
+
+    let dir = std::path::Path::new(dest_path)
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or_else(|| std::path::Path::new("."));
+    File::open(dir)?.sync_all()?;
+
+    Ok(())
+}
+
+// Parses `body` as a complete sequence of pre-header-era records
+// (`[16B Unix Millis Timestamp] [4B key len] [4B val len] [key] [val] [4B CRC32]`,
+// the format in use when `FileBackend::upgrade`'s header rewrite was
+// introduced) and re-encodes each one in the current plaintext record
+// format. Returns `None` if any record is truncated, fails its CRC32, or
+// leaves unparsed trailing bytes -- i.e. `body` isn't actually a legacy WAL,
+// and `upgrade` must not adopt it.
+fn migrate_legacy_records(body: &[u8]) -> Option<Vec<u8>> {
+    let mut pos = 0usize;
+    let mut migrated = Vec::new();
+
+    while pos < body.len() {
+        if pos + 24 > body.len() {
+            return None;
+        }
+        let timestamp_bytes: [u8; 16] = body[pos..pos + 16].try_into().ok()?;
+        let key_len = u32::from_be_bytes(body[pos + 16..pos + 20].try_into().ok()?) as usize;
+        let val_len = u32::from_be_bytes(body[pos + 20..pos + 24].try_into().ok()?) as usize;
+
+        let record_len = 24usize.checked_add(key_len)?.checked_add(val_len)?.checked_add(4)?;
+        if pos + record_len > body.len() {
+            return None;
+        }
+
+        let key = body[pos + 24..pos + 24 + key_len].to_vec();
+        let val = body[pos + 24 + key_len..pos + 24 + key_len + val_len].to_vec();
+        let stored_crc =
+            u32::from_be_bytes(body[pos + record_len - 4..pos + record_len].try_into().ok()?);
+
+        let mut hasher = Hasher::new();
+        hasher.update(&body[pos..pos + record_len - 4]);
+        if hasher.finalize() != stored_crc {
+            return None;
+        }
+
+        let millis = u128::from_be_bytes(timestamp_bytes);
+        let timestamp = UNIX_EPOCH + std::time::Duration::from_millis(millis as u64);
+        let mut entry = LogEntry { timestamp, key, val };
+        migrated.extend(entry.to_binary_log(None, false));
+
+        pos += record_len;
+    }
+
+    Some(migrated)
 }
 
 struct LogEntry {
     timestamp: SystemTime,
-    key: String,
-    val: String,
+    key: Vec<u8>,
+    val: Vec<u8>,
 }
 
 impl LogEntry {
-    pub fn new(key: String, val: String) -> Self {
+    pub fn new(key: Vec<u8>, val: Vec<u8>) -> Self {
         Self {
             timestamp: SystemTime::now(),
             key: key,
             val: val,
         }
     }
-    pub fn to_binary_log(&mut self) -> Vec<u8> {
-        let binary_key = self.key.as_bytes();
-        let binary_val = self.val.as_bytes();
+    pub fn to_binary_log(&mut self, encryption: Option<&EncryptionKey>, compress: bool) -> Vec<u8> {
+        let binary_key = &self.key;
+        let binary_val = &self.val;
 
-        // Prepare the data to write
         let key_len = (binary_key.len() as u32).to_be_bytes();
+        // Original (uncompressed) value length, so `get` can preallocate the
+        // exact output buffer regardless of what's actually stored on disk.
         let val_len = (binary_val.len() as u32).to_be_bytes();
 
-        // 16 B timestamp + 4 B key len + 4 B val len + actual key + actual val
-        let mut log_buf = vec![];
+        let (codec, stored_val) = if compress {
+            (Codec::Lz4, lz4_compress(binary_val))
+        } else {
+            (Codec::None, binary_val.to_vec())
+        };
+        let stored_len = (stored_val.len() as u32).to_be_bytes();
+
         let curr_time = self
             .timestamp
             .duration_since(UNIX_EPOCH)
             .unwrap()
             .as_millis();
 
-        // Write timestamp (16 bytes)
+        let mut log_buf = vec![];
+        // Write timestamp (16 bytes) - stays in the clear in both modes so
+        // replay can at least order records without decrypting them.
         log_buf.write(&curr_time.to_be_bytes()).unwrap();
-        // Write key length (4 bytes)
-        log_buf.write(&key_len).unwrap();
-        // Write value length (4 bytes)
-        log_buf.write(&val_len).unwrap();
 
-        // Write key
-        log_buf.write(&binary_key).unwrap();
-        // Write val
-        log_buf.write(&binary_val).unwrap();
+        match encryption {
+            None => {
+                // 4 B key len + 4 B original val len + 1 B codec + 4 B stored
+                // (possibly compressed) len + actual key + stored val + 4 B CRC32
+                log_buf.write(&key_len).unwrap();
+                log_buf.write(&val_len).unwrap();
+                log_buf.write(&[codec.to_byte()]).unwrap();
+                log_buf.write(&stored_len).unwrap();
+                log_buf.write(&binary_key).unwrap();
+                log_buf.write(&stored_val).unwrap();
+
+                // CRC32 over everything written so far (timestamp + lens + codec + key + val)
+                let mut hasher = Hasher::new();
+                hasher.update(&log_buf);
+                log_buf.write(&hasher.finalize().to_be_bytes()).unwrap();
+            }
+            Some(key) => {
+                // Seal [key_len][val_len][codec][stored_len][key][stored val]
+                // as a single AEAD payload.
+                let mut plaintext = vec![];
+                plaintext.write_all(&key_len).unwrap();
+                plaintext.write_all(&val_len).unwrap();
+                plaintext.write_all(&[codec.to_byte()]).unwrap();
+                plaintext.write_all(&stored_len).unwrap();
+                plaintext.write_all(&binary_key).unwrap();
+                plaintext.write_all(&stored_val).unwrap();
+
+                let (nonce, ciphertext) = key.seal(&plaintext);
+                let ciphertext_len = (ciphertext.len() as u32).to_be_bytes();
+
+                log_buf.write_all(&nonce).unwrap();
+                log_buf.write_all(&ciphertext_len).unwrap();
+                log_buf.write_all(&ciphertext).unwrap();
+            }
+        }
 
         return log_buf;
     }
 }
 
-impl StorageEngine {
-    pub fn new(db_file: &String) -> Self {
+impl FileBackend {
+    pub fn new(db_file: &String) -> io::Result<Self> {
+        Self::open(db_file, None, false)
+    }
+
+    // Opens (creating if needed) an encrypted engine. `algo` only matters for
+    // a brand-new file: it picks which AEAD to seal records with and is
+    // recorded in the header. An existing encrypted file always uses the
+    // algorithm already stored in its header, re-deriving the key from the
+    // passphrase and stored salt.
+    //
+    // Returns an error (instead of destroying the file) if the passphrase is
+    // wrong: see `load_key_pos_map_from_file`.
+    pub fn new_with_passphrase(
+        db_file: &String,
+        passphrase: &str,
+        algo: EncryptionAlgo,
+    ) -> io::Result<Self> {
+        Self::open(db_file, Some((passphrase, algo)), false)
+    }
+
+    // Like `new`, but newly-written records are LZ4-compressed. Existing
+    // records, compressed or not, keep replaying correctly either way since
+    // the codec is stored per record.
+    pub fn new_with_compression(db_file: &String, compress: bool) -> io::Result<Self> {
+        Self::open(db_file, None, compress)
+    }
+
+    pub fn new_with_passphrase_and_compression(
+        db_file: &String,
+        passphrase: &str,
+        algo: EncryptionAlgo,
+        compress: bool,
+    ) -> io::Result<Self> {
+        Self::open(db_file, Some((passphrase, algo)), compress)
+    }
+
+    fn open(
+        db_file: &String,
+        passphrase: Option<(&str, EncryptionAlgo)>,
+        compress: bool,
+    ) -> io::Result<Self> {
+        // A pre-header file (or one from an older format version) gets rewritten
+        // in place before we ever open it for normal use.
+        Self::upgrade(db_file)?;
+
         let wal_file = OpenOptions::new()
             .read(true)
             .write(true)
@@ -146,98 +608,349 @@ impl StorageEngine {
             .open(db_file)
             .unwrap();
 
-        let mut storage_engine = StorageEngine {
+        let file_len = wal_file.metadata().unwrap().len();
+        let salt: [u8; SALT_LEN];
+        let stored_algo: Option<EncryptionAlgo>;
+
+        if file_len == 0 {
+            stored_algo = passphrase.map(|(_, algo)| algo);
+            salt = match stored_algo {
+                Some(_) => {
+                    let mut s = [0u8; SALT_LEN];
+                    OsRng.fill_bytes(&mut s);
+                    s
+                }
+                None => [0u8; SALT_LEN],
+            };
+
+            let mut header = WAL_MAGIC.to_vec();
+            header.push(WAL_FORMAT_VERSION);
+            header.push(stored_algo.map(EncryptionAlgo::to_byte).unwrap_or(0));
+            header.extend_from_slice(&salt);
+            wal_file.write_all_at(&header, 0).unwrap();
+        } else {
+            let mut header = [0u8; HEADER_LEN as usize];
+            wal_file.read_exact_at(&mut header, 0).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "db file is too short to contain a valid waldb header",
+                )
+            })?;
+            if header[..WAL_MAGIC.len()] != WAL_MAGIC {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "db file does not start with the waldb magic signature",
+                ));
+            }
+            if header[WAL_MAGIC.len()] != WAL_FORMAT_VERSION {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "db file was written with an unsupported format version",
+                ));
+            }
+            stored_algo = EncryptionAlgo::from_byte(header[WAL_MAGIC.len() + 1]).unwrap();
+            salt = header[WAL_MAGIC.len() + 2..].try_into().unwrap();
+        }
+
+        let encryption = match (stored_algo, passphrase) {
+            (Some(algo), Some((phrase, _))) => Some(EncryptionKey::derive(phrase, &salt, algo)),
+            (None, None) => None,
+            (Some(_), None) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "db file is encrypted; a passphrase is required",
+                ));
+            }
+            (None, Some(_)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "db file is plaintext; cannot open it with a passphrase",
+                ));
+            }
+        };
+
+        let mut storage_engine = FileBackend {
             db_file: wal_file,
+            path: db_file.clone(),
             key_position_map: HashMap::new(),
             sequence_number: 0,
+            encryption,
+            salt,
+            compress,
+            compaction_ratio: Self::DEFAULT_COMPACTION_RATIO,
         };
 
-        storage_engine.load_key_pos_map_from_file();
+        storage_engine.load_key_pos_map_from_file()?;
 
         // Return the initialized storage_engine, not a new empty one
-        return storage_engine;
+        Ok(storage_engine)
     }
 
-    fn load_key_pos_map_from_file(&mut self) {
-        let mut mp: HashMap<String, u64> = HashMap::new();
+    // Recognizes a headerless (pre-header-format) db file, or one written with
+    // the original 9-byte (magic + version, no encryption fields) header, and
+    // rewrites it into the current header layout, swapping it in atomically.
+    // Files that already carry a valid current-version header are left untouched.
+    //
+    // A candidate legacy body is only ever adopted if it actually replays as a
+    // sequence of legacy records (see `migrate_legacy_records`); anything else
+    // (a bit-flipped header, or an unrelated file) is rejected with an `Err`
+    // instead of being silently rewritten into an empty, valid-looking WAL.
+    fn upgrade(db_file: &String) -> io::Result<()> {
+        let existing = match std::fs::read(db_file) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+
+        if existing.is_empty() {
+            // Brand new file; `open` will write a current-format header.
+            return Ok(());
+        }
+
+        let body = if !existing.starts_with(&WAL_MAGIC) {
+            // No header at all: the very first on-disk format.
+            &existing[..]
+        } else if existing.len() >= 9 && existing[WAL_MAGIC.len()] == 1 {
+            // Version 1 header (magic + version, 9 bytes, no encryption fields).
+            &existing[9..]
+        } else {
+            // Already current, or an unrecognized version: leave it for `open`
+            // to validate and report.
+            return Ok(());
+        };
+
+        let migrated_body = migrate_legacy_records(body).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "db file does not start with the waldb magic signature and does not parse as a legacy WAL",
+            )
+        })?;
+
+        let mut upgraded = WAL_MAGIC.to_vec();
+        upgraded.push(WAL_FORMAT_VERSION);
+        upgraded.push(0); // no encryption algorithm
+        upgraded.extend_from_slice(&[0u8; SALT_LEN]); // no salt
+        upgraded.extend_from_slice(&migrated_body);
+
+        let tmp_path = format!("{}.upgrade.tmp", db_file);
+        write_and_rename_durably(&tmp_path, db_file, &upgraded)?;
+
+        Ok(())
+    }
+
+    // Replays the WAL into `key_position_map`. On an encrypted engine, a
+    // failed AEAD check on the very first record (i.e. nothing has
+    // authenticated yet) is treated as "wrong passphrase or not actually
+    // encrypted" rather than a torn tail, since a genuine torn write only
+    // ever clips the *end* of a file that previously replayed fine. Returning
+    // an error here instead of truncating is what keeps a passphrase typo
+    // from destroying the database.
+    fn load_key_pos_map_from_file(&mut self) -> io::Result<()> {
+        let mut mp: HashMap<Vec<u8>, u64> = HashMap::new();
+        self.sequence_number = 0;
 
         // Get the file size
         let file_size = self.db_file.metadata().unwrap().len();
 
-        let mut current_pos: u64 = 0;
-
-        // Read through the entire file
-        while current_pos < file_size {
-            // Read timestamp (16 bytes) - we don't need it for the map
-            let mut _timestamp = [0u8; 16];
-            if self
-                .db_file
-                .read_exact_at(&mut _timestamp, current_pos)
-                .is_err()
-            {
-                break;
-            }
+        // Records start right after the fixed-size file header.
+        let mut current_pos: u64 = HEADER_LEN;
 
-            // Read key length (4 bytes)
-            let mut key_len_bytes = [0u8; 4];
+        // Read through the entire file, stopping the moment something looks torn
+        // (short read, CRC mismatch, or failed AEAD auth) rather than trusting
+        // the rest of the file.
+        'replay: while current_pos < file_size {
+            // Read timestamp (16 bytes)
+            let mut timestamp = [0u8; 16];
             if self
                 .db_file
-                .read_exact_at(&mut key_len_bytes, current_pos + 16)
+                .read_exact_at(&mut timestamp, current_pos)
                 .is_err()
             {
                 break;
             }
-            let key_len = u32::from_be_bytes(key_len_bytes) as u64;
 
-            // Read value length (4 bytes)
-            let mut val_len_bytes = [0u8; 4];
-            if self
-                .db_file
-                .read_exact_at(&mut val_len_bytes, current_pos + 20)
-                .is_err()
-            {
-                break;
-            }
-            let val_len = u32::from_be_bytes(val_len_bytes) as u64;
+            let (key, record_len, is_tombstone) = match &self.encryption {
+                None => match self.read_plaintext_record(current_pos, file_size, &timestamp) {
+                    Some(result) => result,
+                    None => break 'replay,
+                },
+                Some(encryption) => {
+                    match self.read_encrypted_record(current_pos, file_size, encryption) {
+                        Some(result) => result,
+                        None if current_pos == HEADER_LEN => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "failed to authenticate the first record: wrong passphrase, or the file is corrupt",
+                            ));
+                        }
+                        None => break 'replay,
+                    }
+                }
+            };
 
-            // Read the key
-            let mut key_buffer = vec![0u8; key_len as usize];
-            if self
-                .db_file
-                .read_exact_at(&mut key_buffer, current_pos + 24)
-                .is_err()
-            {
-                break;
+            // Update the map with this entry's position. This naturally
+            // overwrites older entries with newer ones; a tombstone removes
+            // the key from the live set entirely instead of leaving a
+            // position that `get` would just turn back into `None`, so
+            // `keys`/`iter_keys`/`scan` don't surface deleted keys before the
+            // next compaction.
+            if is_tombstone {
+                mp.remove(&key);
+            } else {
+                mp.insert(key, current_pos);
             }
-            let key = String::from_utf8(key_buffer).unwrap();
-
-            // Update the map with this entry's position
-            // This will naturally overwrite older entries with newer ones
-            mp.insert(key, current_pos);
             self.sequence_number += 1;
 
             // Move to the next entry
-            // Entry size = 16 (timestamp) + 4 (key_len) + 4 (val_len) + key_len + val_len
-            current_pos += 24 + key_len + val_len;
+            current_pos += record_len;
         }
 
-        self.key_position_map = mp
+        // If we stopped before the end of the file, everything past current_pos is
+        // either a torn write or garbage; drop it so future appends start clean.
+        if current_pos < file_size {
+            self.db_file.set_len(current_pos).unwrap();
+        }
+
+        self.key_position_map = mp;
+        Ok(())
     }
 
-    pub fn compact(&mut self) {
-        todo!("not fully implemented");
-        let mut tmp_engine = StorageEngine::new(&"/tmp/tmp_waldb".to_string());
+    // Reads and CRC-verifies a plaintext record starting at `pos` (the
+    // timestamp at `pos` has already been read into `timestamp`). Returns the
+    // decoded key, the record's total length, and whether it's a tombstone
+    // (original val_len == 0), or `None` if the record is short/torn/corrupt.
+    fn read_plaintext_record(
+        &self,
+        pos: u64,
+        file_size: u64,
+        timestamp: &[u8; 16],
+    ) -> Option<(Vec<u8>, u64, bool)> {
+        let mut key_len_bytes = [0u8; 4];
+        self.db_file.read_exact_at(&mut key_len_bytes, pos + 16).ok()?;
+        let key_len = u32::from_be_bytes(key_len_bytes) as u64;
+
+        let mut val_len_bytes = [0u8; 4];
+        self.db_file.read_exact_at(&mut val_len_bytes, pos + 20).ok()?;
+        let is_tombstone = u32::from_be_bytes(val_len_bytes) == 0;
+
+        let mut codec_byte = [0u8; 1];
+        self.db_file.read_exact_at(&mut codec_byte, pos + 24).ok()?;
+
+        let mut stored_len_bytes = [0u8; 4];
+        self.db_file
+            .read_exact_at(&mut stored_len_bytes, pos + 25)
+            .ok()?;
+        let stored_len = u32::from_be_bytes(stored_len_bytes) as u64;
+
+        // A corrupt length field can claim more bytes than the file has left;
+        // treat that the same as a short read instead of letting it panic below.
+        let record_len = 29 + key_len + stored_len + 4;
+        if pos + record_len > file_size {
+            return None;
+        }
+
+        let mut key_buffer = vec![0u8; key_len as usize];
+        self.db_file.read_exact_at(&mut key_buffer, pos + 29).ok()?;
+
+        let mut val_buffer = vec![0u8; stored_len as usize];
+        self.db_file
+            .read_exact_at(&mut val_buffer, pos + 29 + key_len)
+            .ok()?;
+
+        let mut crc_bytes = [0u8; 4];
+        self.db_file
+            .read_exact_at(&mut crc_bytes, pos + 29 + key_len + stored_len)
+            .ok()?;
+        let stored_crc = u32::from_be_bytes(crc_bytes);
 
-        for (key, val) in self.key_position_map.iter() {
-            let v = self.get(key.to_string()).unwrap_or(String::new());
-            tmp_engine.set(key.to_string(), v);
+        let mut hasher = Hasher::new();
+        hasher.update(timestamp);
+        hasher.update(&key_len_bytes);
+        hasher.update(&val_len_bytes);
+        hasher.update(&codec_byte);
+        hasher.update(&stored_len_bytes);
+        hasher.update(&key_buffer);
+        hasher.update(&val_buffer);
+        if hasher.finalize() != stored_crc {
+            // Checksum mismatch: this is a torn write or bit flip at the tail.
+            return None;
         }
+
+        Some((key_buffer, record_len, is_tombstone))
     }
 
-    pub fn set(&mut self, key: String, val: String) {
+    // Reads, decrypts, and authenticates an encrypted record starting at
+    // `pos`. Returns the decoded key, the record's total length, and whether
+    // it's a tombstone, or `None` if the record is short/torn or fails AEAD
+    // authentication.
+    fn read_encrypted_record(
+        &self,
+        pos: u64,
+        file_size: u64,
+        encryption: &EncryptionKey,
+    ) -> Option<(Vec<u8>, u64, bool)> {
+        let mut nonce = [0u8; 12];
+        self.db_file.read_exact_at(&mut nonce, pos + 16).ok()?;
+
+        let mut ciphertext_len_bytes = [0u8; 4];
+        self.db_file
+            .read_exact_at(&mut ciphertext_len_bytes, pos + 28)
+            .ok()?;
+        let ciphertext_len = u32::from_be_bytes(ciphertext_len_bytes) as u64;
+
+        let record_len = 16 + 12 + 4 + ciphertext_len;
+        if pos + record_len > file_size {
+            return None;
+        }
+
+        let mut ciphertext = vec![0u8; ciphertext_len as usize];
+        self.db_file.read_exact_at(&mut ciphertext, pos + 32).ok()?;
+
+        let plaintext = encryption.open(&nonce, &ciphertext).ok()?;
+        if plaintext.len() < 13 {
+            return None;
+        }
+        let key_len = u32::from_be_bytes(plaintext[0..4].try_into().unwrap()) as usize;
+        if plaintext.len() < 13 + key_len {
+            return None;
+        }
+        let key = plaintext[13..13 + key_len].to_vec();
+        let is_tombstone = u32::from_be_bytes(plaintext[4..8].try_into().unwrap()) == 0;
+
+        Some((key, record_len, is_tombstone))
+    }
+
+    // Compacting is worth its cost once dead versions pile up: once
+    // sequence_number (total records ever written) outnumbers live keys by
+    // more than this ratio, most of the log is churn from overwrites/deletes.
+    // Overridable per engine with `set_compaction_ratio`.
+    const DEFAULT_COMPACTION_RATIO: i32 = 4;
+
+    // Overrides the auto-compaction trigger ratio for this engine (default
+    // `DEFAULT_COMPACTION_RATIO`). A lower ratio compacts more eagerly, at
+    // the cost of doing the rewrite more often.
+    pub fn set_compaction_ratio(&mut self, ratio: i32) {
+        self.compaction_ratio = ratio;
+    }
+
+    fn maybe_compact(&mut self) {
+        let live_keys = self.key_position_map.len() as i32;
+        if live_keys > 0 && self.sequence_number > live_keys * self.compaction_ratio {
+            self.compact();
+        }
+    }
+}
+
+impl StorageBackend for FileBackend {
+    fn set(&mut self, key: Vec<u8>, val: Vec<u8>) {
+        // An empty value is a tombstone (see the WAL line format comment
+        // above); it must not leave a live position in `key_position_map`,
+        // or `keys`/`iter_keys`/`scan` would surface a deleted key right up
+        // until the next compaction.
+        let is_tombstone = val.is_empty();
         let mut entry = LogEntry::new(key.clone(), val);
 
-        let binary_log_entry = entry.to_binary_log();
+        let binary_log_entry = entry.to_binary_log(self.encryption.as_ref(), self.compress);
 
         // Get current file length to append at the end
         let file_len = self.db_file.metadata().unwrap().len();
@@ -247,43 +960,513 @@ impl StorageEngine {
         self.sequence_number += 1;
 
         // Update the key position map
-        self.key_position_map.insert(key, file_len);
+        if is_tombstone {
+            self.key_position_map.remove(&key);
+        } else {
+            self.key_position_map.insert(key, file_len);
+        }
+
+        self.maybe_compact();
     }
 
-    pub fn get(&mut self, key: String) -> Option<String> {
+    fn get(&mut self, key: Vec<u8>) -> io::Result<Option<Vec<u8>>> {
         let key_start_pos = match self.key_position_map.get(&key) {
-            Some(pos) => pos,
-            None => return None, // Key doesn't exist, return None instead of panicking
+            Some(pos) => *pos,
+            None => return Ok(None), // Key doesn't exist, return None instead of panicking
         };
 
-        // Skip timestamp (16 bytes) and read key length
-        let mut key_len = [0u8; 4];
-        self.db_file
-            .read_exact_at(&mut key_len, *key_start_pos + 16)
-            .unwrap();
+        match &self.encryption {
+            None => {
+                // Skip timestamp (16 bytes) and read key length
+                let mut key_len = [0u8; 4];
+                self.db_file
+                    .read_exact_at(&mut key_len, key_start_pos + 16)
+                    .unwrap();
 
-        // Read value length
-        let mut val_len = [0u8; 4];
-        self.db_file
-            .read_exact_at(&mut val_len, *key_start_pos + 20)
+                // Read the original (uncompressed) value length
+                let mut val_len = [0u8; 4];
+                self.db_file
+                    .read_exact_at(&mut val_len, key_start_pos + 20)
+                    .unwrap();
+
+                let key_len_u32 = u32::from_be_bytes(key_len);
+                let val_len_u32 = u32::from_be_bytes(val_len);
+                if val_len_u32 == 0 {
+                    return Ok(None);
+                }
+
+                let mut codec_byte = [0u8; 1];
+                self.db_file
+                    .read_exact_at(&mut codec_byte, key_start_pos + 24)
+                    .unwrap();
+                let codec = Codec::from_byte(codec_byte[0])?;
+
+                let mut stored_len = [0u8; 4];
+                self.db_file
+                    .read_exact_at(&mut stored_len, key_start_pos + 25)
+                    .unwrap();
+                let stored_len_u32 = u32::from_be_bytes(stored_len);
+
+                // Skip the key data and read the stored (possibly compressed) value
+                let mut val_buffer = vec![0u8; stored_len_u32 as usize];
+                self.db_file
+                    .read_exact_at(&mut val_buffer, key_start_pos + 29 + key_len_u32 as u64)
+                    .unwrap();
+
+                let val_bytes = match codec {
+                    Codec::None => val_buffer,
+                    Codec::Lz4 => lz4_decompress(&val_buffer, val_len_u32 as usize)
+                        .map_err(|_| {
+                            io::Error::new(io::ErrorKind::InvalidData, "corrupt LZ4 record")
+                        })?,
+                };
+
+                Ok(Some(val_bytes))
+            }
+            Some(encryption) => {
+                let mut nonce = [0u8; 12];
+                self.db_file
+                    .read_exact_at(&mut nonce, key_start_pos + 16)
+                    .unwrap();
+
+                let mut ciphertext_len_bytes = [0u8; 4];
+                self.db_file
+                    .read_exact_at(&mut ciphertext_len_bytes, key_start_pos + 28)
+                    .unwrap();
+                let ciphertext_len = u32::from_be_bytes(ciphertext_len_bytes) as usize;
+
+                let mut ciphertext = vec![0u8; ciphertext_len];
+                self.db_file
+                    .read_exact_at(&mut ciphertext, key_start_pos + 32)
+                    .unwrap();
+
+                let plaintext = encryption.open(&nonce, &ciphertext)?;
+                let val_len =
+                    u32::from_be_bytes(plaintext[4..8].try_into().unwrap()) as usize;
+                if val_len == 0 {
+                    return Ok(None);
+                }
+                let key_len = u32::from_be_bytes(plaintext[0..4].try_into().unwrap()) as usize;
+                let codec = Codec::from_byte(plaintext[8])?;
+                let stored_len =
+                    u32::from_be_bytes(plaintext[9..13].try_into().unwrap()) as usize;
+                let val_start = 13 + key_len;
+                let stored_val = &plaintext[val_start..val_start + stored_len];
+
+                let val_bytes = match codec {
+                    Codec::None => stored_val.to_vec(),
+                    Codec::Lz4 => lz4_decompress(stored_val, val_len).map_err(|_| {
+                        io::Error::new(io::ErrorKind::InvalidData, "corrupt LZ4 record")
+                    })?,
+                };
+
+                Ok(Some(val_bytes))
+            }
+        }
+    }
+
+    fn delete(&mut self, key: Vec<u8>) {
+        self.set(key, Vec::new());
+    }
+
+    // Rewrites the WAL with only the latest live value for each key, dropping
+    // tombstones entirely, and emits them in sorted key order (MTBL/SSTable
+    // style) so the result is useful for range scans and future merges.
+    //
+    // The rewrite happens in a temp file and is swapped in with a single
+    // `rename`, so a crash at any point leaves either the old file or the new
+    // one fully intact, never a half-written db.
+    fn compact(&mut self) {
+        let mut keys: Vec<Vec<u8>> = self.key_position_map.keys().cloned().collect();
+        keys.sort();
+
+        let mut rewritten = WAL_MAGIC.to_vec();
+        rewritten.push(WAL_FORMAT_VERSION);
+        rewritten.push(
+            self.encryption
+                .as_ref()
+                .map(|enc| enc.algo.to_byte())
+                .unwrap_or(0),
+        );
+        rewritten.extend_from_slice(&self.salt);
+
+        for key in &keys {
+            // `get` already returns None for tombstones (val_len == 0), so
+            // skipping None here is what drops them from the rewritten log.
+            // A corrupt/unauthenticated record is dropped the same way rather
+            // than aborting the whole compaction.
+            if let Ok(Some(val)) = self.get(key.clone()) {
+                let mut entry = LogEntry::new(key.clone(), val);
+                rewritten.extend(entry.to_binary_log(self.encryption.as_ref(), self.compress));
+            }
+        }
+
+        let tmp_path = format!("{}.compact.tmp", self.path);
+        write_and_rename_durably(&tmp_path, &self.path, &rewritten).unwrap();
+
+        self.db_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&self.path)
             .unwrap();
 
-        let key_len_u32 = u32::from_be_bytes(key_len);
-        let val_len_u32 = u32::from_be_bytes(val_len);
-        if val_len_u32 == 0 {
+        // Rebuilds key_position_map and resets sequence_number against the
+        // freshly-compacted file. We just wrote this file ourselves with the
+        // same key, so a replay failure here would mean a bug in compact(),
+        // not a bad passphrase.
+        self.load_key_pos_map_from_file()
+            .expect("freshly compacted file failed to replay");
+    }
+
+    fn keys(&self) -> Vec<Vec<u8>> {
+        self.key_position_map.keys().cloned().collect()
+    }
+}
+
+// Keeps the exact same semantics as `FileBackend` (append-only log of
+// length-prefixed records, tombstones for deletes, sorted-rewrite compaction)
+// but over an in-process `Vec<u8>` instead of a real file. This makes the
+// replay/compaction logic unit-testable without touching disk, and runs
+// anywhere `std` does, not just Unix.
+struct MemoryBackend {
+    log: Vec<u8>,
+    key_position_map: HashMap<Vec<u8>, u64>,
+    sequence_number: i32,
+    // Whether newly-written records should be LZ4-compressed; see
+    // `FileBackend::compress`.
+    compress: bool,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self {
+            log: Vec::new(),
+            key_position_map: HashMap::new(),
+            sequence_number: 0,
+            compress: false,
+        }
+    }
+
+    pub fn new_with_compression(compress: bool) -> Self {
+        Self {
+            log: Vec::new(),
+            key_position_map: HashMap::new(),
+            sequence_number: 0,
+            compress,
+        }
+    }
+
+    // Reads a record's value bytes out of the in-memory log at `pos`.
+    // Mirrors `FileBackend::get`'s record layout exactly, minus the CRC
+    // (there's nothing to protect against torn writes in memory).
+    fn read_val_at(&self, pos: u64) -> Option<Vec<u8>> {
+        let pos = pos as usize;
+        let val_len = u32::from_be_bytes(self.log[pos + 20..pos + 24].try_into().unwrap());
+        if val_len == 0 {
             return None;
         }
 
-        // Skip the key data and read the value
-        let mut val_buffer = vec![0u8; val_len_u32 as usize];
-        self.db_file
-            .read_exact_at(&mut val_buffer, *key_start_pos + 24 + key_len_u32 as u64)
+        let key_len = u32::from_be_bytes(self.log[pos + 16..pos + 20].try_into().unwrap());
+        let codec = Codec::from_byte(self.log[pos + 24]).unwrap();
+        let stored_len = u32::from_be_bytes(self.log[pos + 25..pos + 29].try_into().unwrap());
+
+        let val_start = pos + 29 + key_len as usize;
+        let stored_val = &self.log[val_start..val_start + stored_len as usize];
+
+        let val_bytes = match codec {
+            Codec::None => stored_val.to_vec(),
+            Codec::Lz4 => lz4_decompress(stored_val, val_len as usize).unwrap(),
+        };
+        Some(val_bytes)
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn set(&mut self, key: Vec<u8>, val: Vec<u8>) {
+        // See `FileBackend::set`: a tombstone must not leave a live position
+        // in `key_position_map`.
+        let is_tombstone = val.is_empty();
+        let mut entry = LogEntry::new(key.clone(), val);
+        let pos = self.log.len() as u64;
+
+        self.log.extend(entry.to_binary_log(None, self.compress));
+        self.sequence_number += 1;
+        if is_tombstone {
+            self.key_position_map.remove(&key);
+        } else {
+            self.key_position_map.insert(key, pos);
+        }
+    }
+
+    fn get(&mut self, key: Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+        let pos = match self.key_position_map.get(&key) {
+            Some(pos) => *pos,
+            None => return Ok(None),
+        };
+        Ok(self.read_val_at(pos))
+    }
+
+    fn delete(&mut self, key: Vec<u8>) {
+        self.set(key, Vec::new());
+    }
+
+    fn compact(&mut self) {
+        let mut keys: Vec<Vec<u8>> = self.key_position_map.keys().cloned().collect();
+        keys.sort();
+
+        let mut rewritten = Vec::new();
+        let mut rebuilt_map = HashMap::new();
+
+        for key in &keys {
+            if let Ok(Some(val)) = self.get(key.clone()) {
+                let pos = rewritten.len() as u64;
+                let mut entry = LogEntry::new(key.clone(), val);
+                rewritten.extend(entry.to_binary_log(None, self.compress));
+                rebuilt_map.insert(key.clone(), pos);
+            }
+        }
+
+        self.log = rewritten;
+        self.key_position_map = rebuilt_map;
+        self.sequence_number = self.key_position_map.len() as i32;
+    }
+
+    fn keys(&self) -> Vec<Vec<u8>> {
+        self.key_position_map.keys().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    // `MemoryBackend` exists specifically so replay/compaction logic can be
+    // exercised without touching real files; these cases cover it.
+    #[test]
+    fn memory_backend_set_get_delete() {
+        let mut be = MemoryBackend::new();
+        be.set(b"a".to_vec(), b"1".to_vec());
+        be.set(b"b".to_vec(), b"2".to_vec());
+        assert_eq!(be.get(b"a".to_vec()).unwrap(), Some(b"1".to_vec()));
+
+        be.delete(b"a".to_vec());
+        assert_eq!(be.get(b"a".to_vec()).unwrap(), None);
+        assert_eq!(be.get(b"b".to_vec()).unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn memory_backend_compact_drops_tombstones_and_keeps_latest() {
+        let mut be = MemoryBackend::new();
+        be.set(b"a".to_vec(), b"1".to_vec());
+        be.set(b"a".to_vec(), b"2".to_vec());
+        be.set(b"b".to_vec(), b"stale".to_vec());
+        be.delete(b"b".to_vec());
+
+        be.compact();
+
+        assert_eq!(be.keys(), vec![b"a".to_vec()]);
+        assert_eq!(be.get(b"a".to_vec()).unwrap(), Some(b"2".to_vec()));
+        assert_eq!(be.get(b"b".to_vec()).unwrap(), None);
+    }
+
+    #[test]
+    fn memory_backend_lz4_round_trip() {
+        let mut be = MemoryBackend::new_with_compression(true);
+        let val = b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa".to_vec();
+        be.set(b"k".to_vec(), val.clone());
+        assert_eq!(be.get(b"k".to_vec()).unwrap(), Some(val));
+    }
+
+    #[test]
+    fn scan_range_is_half_open_and_sorted() {
+        let mut be = MemoryBackend::new();
+        for k in ["a", "b", "c", "d"] {
+            be.set(k.as_bytes().to_vec(), b"v".to_vec());
+        }
+
+        let pairs = be.scan(b"b", b"d").unwrap();
+        let keys: Vec<Vec<u8>> = pairs.into_iter().map(|(k, _)| k).collect();
+        // start is inclusive, end is exclusive: "b" and "c", not "d".
+        assert_eq!(keys, vec![b"b".to_vec(), b"c".to_vec()]);
+    }
+
+    #[test]
+    fn iter_keys_excludes_tombstones() {
+        let mut be = MemoryBackend::new();
+        be.set(b"a".to_vec(), b"1".to_vec());
+        be.set(b"b".to_vec(), b"2".to_vec());
+        be.delete(b"b".to_vec());
+        assert_eq!(be.iter_keys(), vec![b"a".to_vec()]);
+    }
+
+    // FileBackend-specific cases need a real file, so each test works in its
+    // own uniquely-named temp path and cleans up after itself.
+    fn temp_wal_path(name: &str) -> String {
+        static COUNTER: AtomicU32 = AtomicU32::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        std::env::temp_dir()
+            .join(format!("waldb-test-{}-{}-{}.wal", std::process::id(), name, n))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn file_backend_header_survives_reopen() {
+        let path = temp_wal_path("header");
+        {
+            let mut be = FileBackend::new(&path).unwrap();
+            be.set(b"k".to_vec(), b"v".to_vec());
+        }
+        {
+            let mut be = FileBackend::new(&path).unwrap();
+            assert_eq!(be.get(b"k".to_vec()).unwrap(), Some(b"v".to_vec()));
+        }
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn file_backend_torn_write_recovers_valid_prefix() {
+        let path = temp_wal_path("torn");
+        {
+            let mut be = FileBackend::new(&path).unwrap();
+            be.set(b"a".to_vec(), b"1".to_vec());
+            be.set(b"b".to_vec(), b"2".to_vec());
+        }
+
+        // Simulate a crash mid-append by chopping off the last few bytes of
+        // the second record.
+        let full_len = std::fs::metadata(&path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(full_len - 3).unwrap();
+        drop(file);
+
+        let mut be = FileBackend::new(&path).unwrap();
+        assert_eq!(be.get(b"a".to_vec()).unwrap(), Some(b"1".to_vec()));
+        assert_eq!(be.get(b"b".to_vec()).unwrap(), None);
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn file_backend_encryption_round_trip() {
+        let path = temp_wal_path("enc");
+        {
+            let mut be = FileBackend::new_with_passphrase(
+                &path,
+                "correct-horse",
+                EncryptionAlgo::ChaCha20Poly1305,
+            )
+            .unwrap();
+            be.set(b"secret".to_vec(), b"value".to_vec());
+        }
+
+        let mut be = FileBackend::new_with_passphrase(
+            &path,
+            "correct-horse",
+            EncryptionAlgo::ChaCha20Poly1305,
+        )
+        .unwrap();
+        assert_eq!(be.get(b"secret".to_vec()).unwrap(), Some(b"value".to_vec()));
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    // Regression test for the fix above: a wrong passphrase must never
+    // truncate the file.
+    #[test]
+    fn file_backend_wrong_passphrase_does_not_destroy_data() {
+        let path = temp_wal_path("wrongpass");
+        {
+            let mut be = FileBackend::new_with_passphrase(
+                &path,
+                "correct-horse",
+                EncryptionAlgo::ChaCha20Poly1305,
+            )
             .unwrap();
+            be.set(b"k".to_vec(), b"v".to_vec());
+        }
+        let len_before = std::fs::metadata(&path).unwrap().len();
 
-        return Some(String::from_utf8(val_buffer).unwrap());
+        let err = FileBackend::new_with_passphrase(
+            &path,
+            "wrong-password",
+            EncryptionAlgo::ChaCha20Poly1305,
+        )
+        .err();
+        assert!(err.is_some());
+
+        let len_after = std::fs::metadata(&path).unwrap().len();
+        assert_eq!(len_before, len_after);
+
+        let mut be = FileBackend::new_with_passphrase(
+            &path,
+            "correct-horse",
+            EncryptionAlgo::ChaCha20Poly1305,
+        )
+        .unwrap();
+        assert_eq!(be.get(b"k".to_vec()).unwrap(), Some(b"v".to_vec()));
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    // Regression test for the fix above: an arbitrary non-WAL file must be
+    // rejected, not silently adopted as a legacy WAL and wiped.
+    #[test]
+    fn file_backend_rejects_unparseable_file_instead_of_adopting_it() {
+        let path = temp_wal_path("garbage");
+        std::fs::write(&path, vec![0x42u8; 59]).unwrap();
+
+        let err = FileBackend::new(&path).err();
+        assert!(err.is_some());
+
+        // The original bytes must be untouched; no empty header was written.
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(bytes, vec![0x42u8; 59]);
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn file_backend_rejects_unsupported_format_version() {
+        let path = temp_wal_path("version");
+        let mut header = WAL_MAGIC.to_vec();
+        header.push(WAL_FORMAT_VERSION + 1);
+        header.push(0);
+        header.extend_from_slice(&[0u8; SALT_LEN]);
+        std::fs::write(&path, &header).unwrap();
+
+        let err = FileBackend::new(&path).err();
+        assert!(err.is_some());
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn file_backend_rejects_truncated_header() {
+        let path = temp_wal_path("short-header");
+        std::fs::write(&path, &WAL_MAGIC).unwrap(); // shorter than HEADER_LEN
+
+        let err = FileBackend::new(&path).err();
+        assert!(err.is_some());
+
+        std::fs::remove_file(&path).ok();
     }
 
-    pub fn delete(&mut self, key: String) {
-        self.set(key, String::new());
+    #[test]
+    fn file_backend_rejects_passphrase_on_plaintext_db() {
+        let path = temp_wal_path("plaintext-mismatch");
+        {
+            let mut be = FileBackend::new(&path).unwrap();
+            be.set(b"k".to_vec(), b"v".to_vec());
+        }
+
+        let err =
+            FileBackend::new_with_passphrase(&path, "a-passphrase", EncryptionAlgo::Aes256Gcm)
+                .err();
+        assert!(err.is_some());
+
+        std::fs::remove_file(&path).ok();
     }
 }